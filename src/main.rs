@@ -1,9 +1,8 @@
 use std::{
-    cell::RefCell,
     collections::{BTreeMap, HashMap, VecDeque},
-    io::{Read, Write},
-    os::fd::{AsFd, AsRawFd, RawFd},
-    rc::Rc,
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
     time::{Duration, Instant},
 };
 
@@ -12,23 +11,21 @@ use nix::{
     sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags},
 };
 use nom::{
-    bytes::complete::{take, take_while},
+    bytes::streaming::{tag, take, take_while},
+    character::streaming::char,
     combinator::map_res,
     multi::many,
-    sequence::terminated,
+    sequence::{delimited, terminated},
     IResult, Parser,
 };
-use nom::{bytes::tag, character::complete::char, sequence::delimited};
-use ringbuffer::GrowableAllocRingBuffer;
-use smol::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    LocalExecutor, Timer,
-};
+use ringbuffer::{GrowableAllocRingBuffer, RingBuffer};
 
 #[derive(Default, Debug)]
 struct List {
     content: Vec<String>,
+    /// Fds of clients parked in `BLPOP`, in arrival order. A push that finds
+    /// waiters hands the element straight to the front fd, so blocked clients
+    /// are served first-come-first-served.
     waiting: VecDeque<RawFd>,
 }
 
@@ -36,18 +33,91 @@ struct List {
 struct Database {
     values: HashMap<String, String>,
     lists: HashMap<String, List>,
-    clients: HashMap<RawFd, TcpStream>,
+    clients: HashMap<RawFd, Client>,
+    /// Monotonic expiry generation per key. Each `SET` bumps it, so a timer
+    /// scheduled by an earlier `SET ... PX` carries a stale generation and is
+    /// ignored when it fires — a later overwrite supersedes the old expiry.
+    expiry_generation: HashMap<String, u64>,
 }
 
-enum TimeoutAction {
-    InvalidateEntry(String),
-    StopWaiting(RawFd, String),
+impl Database {
+    /// Advances and returns the expiry generation for `key`, invalidating any
+    /// `InvalidateEntry` timer already scheduled against the previous value.
+    fn bump_expiry_generation(&mut self, key: &str) -> u64 {
+        let generation = self.expiry_generation.get(key).map_or(0, |g| g + 1);
+        self.expiry_generation.insert(key.to_string(), generation);
+        generation
+    }
+}
+
+/// Per-connection state. Reply helpers append into `output` instead of writing
+/// to the socket directly; `flush` drains that buffer with a non-blocking write
+/// and leaves any unsent tail queued for the next `EPOLLOUT`. If the peer stops
+/// reading and the backlog grows past `high_water_mark` the connection is torn
+/// down instead of buffering without bound.
+struct Client {
+    stream: TcpStream,
+    input: GrowableAllocRingBuffer<u8>,
+    output: GrowableAllocRingBuffer<u8>,
+    high_water_mark: usize,
+}
+
+impl Client {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            input: GrowableAllocRingBuffer::new(),
+            output: GrowableAllocRingBuffer::new(),
+            high_water_mark: 16 * 1024 * 1024,
+        }
+    }
+
+    fn enqueue(&mut self, bytes: &[u8]) {
+        self.output.extend(bytes.iter().copied());
+    }
+
+    /// Drains as much of the outbound buffer as the socket will take right now.
+    /// A `WouldBlock` (or short write) leaves the remainder queued so the next
+    /// flush, once the socket is writable again, resumes where it left off; a
+    /// broken pipe surfaces as an error instead of a panic.
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.output.is_empty() {
+            return Ok(());
+        }
+
+        // Copy the backlog out once, then write successive slices of it; the
+        // bytes actually accepted by the socket are dropped from the FIFO in a
+        // single pass afterwards so a large reply isn't re-collected per write.
+        let pending: Vec<u8> = self.output.to_vec();
+        let mut written = 0;
+        let result = loop {
+            if written == pending.len() {
+                break Ok(());
+            }
+            match self.stream.write(&pending[written..]) {
+                Ok(0) => break Ok(()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+
+        for _ in 0..written {
+            _ = self.output.dequeue();
+        }
+
+        result
+    }
+
+    fn over_high_water_mark(&self) -> bool {
+        self.output.len() > self.high_water_mark
+    }
 }
 
 #[derive(Debug)]
 enum Event {
     DataReceived(RawFd),
-    InvalidateEntry(String),
+    InvalidateEntry(String, u64),
     StopWaiting(RawFd, String),
 }
 
@@ -88,6 +158,21 @@ impl Reactor {
             .unwrap();
     }
 
+    /// Re-arms a client's oneshot registration, adding write-interest when the
+    /// client still has buffered output so that the next `EPOLLOUT` resumes the
+    /// flush.
+    fn modify_interest(&mut self, fd: RawFd, want_write: bool) {
+        let mut flags = EpollFlags::EPOLLIN | EpollFlags::EPOLLONESHOT;
+        if want_write {
+            flags |= EpollFlags::EPOLLOUT;
+        }
+
+        let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+        self.epoll
+            .modify(borrowed, &mut EpollEvent::new(flags, fd as u64))
+            .unwrap();
+    }
+
     fn register_timeout(&mut self, timeout: Instant, event: Event) {
         _ = self.timeouts.insert(timeout, event);
     }
@@ -144,320 +229,378 @@ impl Iterator for Reactor {
     }
 }
 
-async fn create_client<'db>(
-    mut stream: TcpStream,
-    db: Rc<RefCell<Database>>,
-    executor: Rc<LocalExecutor<'_>>,
-) {
+fn main() {
+    let mut db = Database::default();
+    let mut reactor = Reactor::default();
+
+    let listener = TcpListener::bind("127.0.0.1:6379").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    reactor.register_oneshot(listener.as_fd());
+
+    while let Some(event) = reactor.next() {
+        dispatch(event, &mut db, &mut reactor, &listener);
+    }
+}
+
+/// Advances the event loop by one event. Keeping this separate from `main`'s
+/// `next()` pump lets the loop be driven deterministically by passing events
+/// straight to `dispatch` instead of waiting on epoll.
+fn dispatch(event: Event, db: &mut Database, reactor: &mut Reactor, listener: &TcpListener) {
+    match event {
+        Event::DataReceived(fd) if fd == listener.as_raw_fd() => {
+            accept_clients(listener, db, reactor);
+            reactor.reactivate_oneshot(listener.as_fd());
+        }
+        Event::DataReceived(fd) => {
+            if !db.clients.contains_key(&fd) {
+                return;
+            }
+
+            match service_client(fd, db, reactor) {
+                ServiceOutcome::Disconnected => disconnect_client(fd, db),
+                ServiceOutcome::Live => rearm(fd, db, reactor),
+            }
+        }
+        Event::InvalidateEntry(key, generation) => {
+            // Only evict if no newer `SET` has superseded this expiry.
+            if db.expiry_generation.get(&key) == Some(&generation) {
+                _ = db.values.remove(&key);
+            }
+        }
+        Event::StopWaiting(fd, key) => {
+            let removed = db.lists.get_mut(&key).is_some_and(|list| {
+                if let Some(idx) = list.waiting.iter().position(|waiting_fd| *waiting_fd == fd) {
+                    _ = list.waiting.remove(idx);
+                    true
+                } else {
+                    false
+                }
+            });
+
+            if removed {
+                if let Some(client) = db.clients.get_mut(&fd) {
+                    send_null_array(client);
+                }
+                rearm(fd, db, reactor);
+            }
+        }
+    }
+}
+
+fn accept_clients(listener: &TcpListener, db: &mut Database, reactor: &mut Reactor) {
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(true).unwrap();
+                reactor.register_oneshot(stream.as_fd());
+                db.clients.insert(stream.as_raw_fd(), Client::new(stream));
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => {
+                println!("Error accepting client: {e}");
+                break;
+            }
+        }
+    }
+}
+
+enum ServiceOutcome {
+    Live,
+    Disconnected,
+}
+
+fn service_client(fd: RawFd, db: &mut Database, reactor: &mut Reactor) -> ServiceOutcome {
     let mut buf = [0; 512];
+
     loop {
-        match stream.read(&mut buf).await {
-            Ok(num_bytes_read) if num_bytes_read > 0 => match parse_array(&buf[..num_bytes_read]) {
-                Ok((_, request)) => {
-                    handle_request(&request, &mut stream, db.clone(), executor.clone()).await
+        let read_result = db.clients.get_mut(&fd).unwrap().stream.read(&mut buf);
+        match read_result {
+            Ok(0) => return ServiceOutcome::Disconnected,
+            Ok(num_bytes_read) => {
+                db.clients
+                    .get_mut(&fd)
+                    .unwrap()
+                    .input
+                    .extend(buf[..num_bytes_read].iter().copied());
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(_) => return ServiceOutcome::Disconnected,
+        }
+
+        // Drain as many complete commands as the buffer currently holds,
+        // leaving any trailing partial frame in place for the next read.
+        // Snapshot the buffer once and advance an offset across it so each
+        // byte is copied a single time regardless of how many commands the
+        // read delivered; the consumed prefix is dropped in one pass after.
+        let bytes: Vec<u8> = db.clients.get(&fd).unwrap().input.to_vec();
+        let mut consumed = 0;
+        loop {
+            match parse_array(&bytes[consumed..]) {
+                Ok((remaining, request)) => {
+                    consumed = bytes.len() - remaining.len();
+
+                    handle_request(&request, fd, db, reactor);
+
+                    if db.clients.get(&fd).is_none_or(Client::over_high_water_mark) {
+                        return ServiceOutcome::Disconnected;
+                    }
                 }
+                Err(nom::Err::Incomplete(_)) => break,
                 Err(e) => {
                     println!("Received invalid request: {e}");
-                    break;
+                    return ServiceOutcome::Disconnected;
                 }
-            },
-            _ => break,
+            }
+        }
+
+        let input = &mut db.clients.get_mut(&fd).unwrap().input;
+        for _ in 0..consumed {
+            _ = input.dequeue();
+        }
+    }
+
+    ServiceOutcome::Live
+}
+
+/// Flushes a client's pending output and re-arms its oneshot registration,
+/// disconnecting it on a write error or once it blows past the high-water mark.
+fn rearm(fd: RawFd, db: &mut Database, reactor: &mut Reactor) {
+    let flush_ok = match db.clients.get_mut(&fd) {
+        Some(client) => client.flush().is_ok(),
+        None => return,
+    };
+    if !flush_ok {
+        return disconnect_client(fd, db);
+    }
+
+    let (over, want_write) = {
+        let client = db.clients.get(&fd).unwrap();
+        (client.over_high_water_mark(), !client.output.is_empty())
+    };
+    if over {
+        return disconnect_client(fd, db);
+    }
+
+    reactor.modify_interest(fd, want_write);
+}
+
+/// Tears down the server-side state that referenced a now-closed connection:
+/// dropping the `Client` closes the socket (and removes it from epoll), and the
+/// fd is purged from every list it was parked on, so no push hands an element
+/// to it. `StopWaiting` timers already scheduled for the fd are not removed
+/// from the reactor; when one fires it acts only if that fd is parked on the
+/// key at that moment.
+fn disconnect_client(fd: RawFd, db: &mut Database) {
+    _ = db.clients.remove(&fd);
+
+    for list in db.lists.values_mut() {
+        if let Some(idx) = list.waiting.iter().position(|waiting_fd| *waiting_fd == fd) {
+            _ = list.waiting.remove(idx);
         }
     }
 }
 
-async fn handle_request(
-    request: &Vec<&str>,
-    stream: &mut TcpStream,
-    db: Rc<RefCell<Database>>,
-    executor: Rc<LocalExecutor<'_>>,
-) {
+fn handle_request(request: &[&str], fd: RawFd, db: &mut Database, reactor: &mut Reactor) {
     println!("Received request: {request:?}");
 
-    match request.as_slice() {
-        ["PING"] => send_bulk_string(stream, "PONG").await,
-        ["ECHO", message] => send_bulk_string(stream, *message).await,
-        ["GET", key] => match db.borrow().values.get(*key) {
-            Some(value) => send_bulk_string(stream, value).await,
-            _ => send_null_bulk_string(stream).await,
+    match request {
+        ["PING"] => send_bulk_string(client(db, fd), "PONG"),
+        ["ECHO", message] => send_bulk_string(client(db, fd), message),
+        ["GET", key] => match db.values.get(*key).cloned() {
+            Some(value) => send_bulk_string(client(db, fd), &value),
+            _ => send_null_bulk_string(client(db, fd)),
         },
         ["SET", key, value] => {
-            db.borrow_mut()
-                .values
-                .insert(key.to_string(), value.to_string());
-
-            send_simple_string(stream, "OK").await
+            db.values.insert(key.to_string(), value.to_string());
+            // A plain `SET` clears any pending expiry on the key.
+            _ = db.bump_expiry_generation(key);
+            send_simple_string(client(db, fd), "OK")
         }
         ["SET", key, value, "PX", timeout_ms] => {
-            db.borrow_mut()
-                .values
-                .insert(key.to_string(), value.to_string());
+            let Ok(timeout_ms) = timeout_ms.parse::<u64>() else {
+                return send_error(client(db, fd), "ERR value is not an integer or out of range");
+            };
+
+            db.values.insert(key.to_string(), value.to_string());
+            let generation = db.bump_expiry_generation(key);
+
+            let timeout = Instant::now() + Duration::from_millis(timeout_ms);
+            reactor.register_timeout(timeout, Event::InvalidateEntry(key.to_string(), generation));
+
+            send_simple_string(client(db, fd), "OK")
+        }
+        ["RPUSH", key, elements @ ..] => {
+            let list = db.lists.entry(key.to_string()).or_default();
+            list.content.extend(elements.iter().map(|e| e.to_string()));
+            let len = list.content.len();
+
+            deliver_to_waiters(key, db, reactor);
+            send_integer(client(db, fd), len)
+        }
+        ["LPUSH", key, elements @ ..] => {
+            let list = db.lists.entry(key.to_string()).or_default();
+            for element in elements.iter() {
+                list.content.insert(0, element.to_string());
+            }
+            let len = list.content.len();
+
+            deliver_to_waiters(key, db, reactor);
+            send_integer(client(db, fd), len)
+        }
+        ["LPOP", key, rest @ ..] => {
+            let count = match rest.first() {
+                Some(s) => match s.parse::<usize>() {
+                    Ok(count) => Some(count),
+                    Err(_) => {
+                        return send_error(
+                            client(db, fd),
+                            "ERR value is not an integer or out of range",
+                        )
+                    }
+                },
+                None => None,
+            };
+            let list = db.lists.entry(key.to_string()).or_default();
+
+            match count {
+                // Explicit count (including 0) always replies with an array.
+                Some(count) => {
+                    let elements = list
+                        .content
+                        .drain(0..count.min(list.content.len()))
+                        .collect::<Vec<_>>();
+                    send_string_array(client(db, fd), &elements)
+                }
+                None if list.content.is_empty() => send_null_bulk_string(client(db, fd)),
+                None => {
+                    let element = list.content.remove(0);
+                    send_bulk_string(client(db, fd), &element)
+                }
+            }
+        }
+        ["LRANGE", key, start_idx, end_idx] => {
+            let (Ok(start_idx), Ok(end_idx)) = (start_idx.parse(), end_idx.parse()) else {
+                return send_error(client(db, fd), "ERR value is not an integer or out of range");
+            };
 
-            let duration = Duration::from_millis(timeout_ms.parse().unwrap());
-            let key = key.to_string();
+            let elements = match db.lists.get(*key) {
+                Some(list) if !list.content.is_empty() => {
+                    let len = list.content.len();
+                    let start = handle_index(start_idx, len);
+                    let end = handle_index(end_idx, len).min(len - 1);
+                    if start >= len || start > end {
+                        Vec::new()
+                    } else {
+                        list.content[start..=end].to_vec()
+                    }
+                }
+                _ => Vec::new(),
+            };
 
-            executor
-                .spawn(async move {
-                    _ = Timer::after(duration).await;
-                    _ = db.borrow_mut().values.remove(&key);
-                })
-                .detach();
+            send_string_array(client(db, fd), &elements)
+        }
+        ["LLEN", key] => {
+            let len = db.lists.get(*key).map_or(0, |list| list.content.len());
+            send_integer(client(db, fd), len)
+        }
+        ["BLPOP", key, timeout] => {
+            let Ok(timeout) = timeout.parse::<f32>() else {
+                return send_error(client(db, fd), "ERR timeout is not a float or out of range");
+            };
 
-            send_simple_string(stream, "OK").await
+            // No element available: park this client. A concurrent push delivers
+            // directly; a positive timeout schedules a `StopWaiting` wakeup that
+            // resolves the block with a null array. `BLPOP key 0` blocks forever.
+            let popped = {
+                let list = db.lists.entry(key.to_string()).or_default();
+                if list.content.is_empty() {
+                    list.waiting.push_back(fd);
+                    None
+                } else {
+                    Some(list.content.remove(0))
+                }
+            };
+
+            match popped {
+                Some(element) => {
+                    send_string_array(client(db, fd), &[key.to_string(), element])
+                }
+                None if timeout > 0.0 => reactor.register_timeout(
+                    Instant::now() + Duration::from_secs_f32(timeout),
+                    Event::StopWaiting(fd, key.to_string()),
+                ),
+                None => {}
+            }
         }
         _ => {}
     }
 }
 
-fn main() {
-    let db = Rc::new(RefCell::new(Database::default()));
-    let executor = Rc::new(LocalExecutor::new());
-
-    let executor_clone = executor.clone();
-    smol::block_on(executor.run(async move {
-        let acceptor = TcpListener::bind("127.0.0.1:6379").await.unwrap();
-
-        while let Ok((stream, _)) = acceptor.accept().await {
-            let db_clone = db.clone();
-            let another_executor_clone = executor_clone.clone();
-
-            executor_clone
-                .spawn(async move {
-                    create_client(stream, db_clone, another_executor_clone.clone()).await
-                })
-                .detach();
+/// Hands freshly pushed elements to clients blocked in `BLPOP`, oldest waiter
+/// first, flushing each recipient's reply as it is produced.
+fn deliver_to_waiters(key: &str, db: &mut Database, reactor: &mut Reactor) {
+    while let Some((waiting_fd, element)) = db.lists.get_mut(key).and_then(|list| {
+        let waiting_fd = *list.waiting.front()?;
+        if list.content.is_empty() {
+            return None;
+        }
+        _ = list.waiting.pop_front();
+        Some((waiting_fd, list.content.remove(0)))
+    }) {
+        if let Some(client) = db.clients.get_mut(&waiting_fd) {
+            send_string_array(client, &[key.to_string(), element]);
         }
-    }));
-
-    // let mut db = Database::default();
-    // let mut streams = HashMap::new();
-    // let mut event_loop = Reactor::default();
-
-    // let listener = TcpListener::bind("127.0.0.1:6379").unwrap();
-    // listener.set_nonblocking(true).unwrap();
-    // event_loop.register_oneshot(listener.as_fd());
-    //
-    // while let Some(event) = event_loop.next() {
-    //     match event {
-    //         Event::DataReceived(fd) if fd == listener.as_raw_fd() => {
-    //             match listener.accept() {
-    //                 Ok((stream, _)) => {
-    //                     stream.set_nonblocking(true).unwrap();
-    //                     event_loop.register_oneshot(stream.as_fd());
-    //                     streams.insert(stream.as_raw_fd(), stream);
-    //                 }
-    //                 Err(e) => println!("Error accepting client: {e}"),
-    //             }
-    //             event_loop.reactivate_oneshot(listener.as_fd());
-    //         }
-    //         Event::DataReceived(fd) => {
-    //             handle_stream(fd, &mut streams, &mut db, &mut event_loop).unwrap();
-    //             event_loop.reactivate_oneshot(streams.get(&fd).unwrap().as_fd());
-    //         }
-    //         Event::StopWaiting(fd, key) => {
-    //             let list = db.lists.get_mut(&key).unwrap();
-    //
-    //             if let Some(wait_idx) = list.waiting.iter().position(|val| *val == fd) {
-    //                 _ = list.waiting.remove(wait_idx);
-    //                 let stream = streams.get_mut(&fd).unwrap();
-    //                 send_null_bulk_string(stream);
-    //             }
-    //         }
-    //         Event::InvalidateEntry(key) => {
-    //             _ = db.values.remove(&key);
-    //         }
-    //         _ => println!("Unknown event received: {event:?}"),
-    //     }
-    // }
-}
-
-// fn handle_stream(
-//     fd: RawFd,
-//     streams: &mut HashMap<RawFd, TcpStream>,
-//     db: &mut Database,
-//     event_loop: &mut Reactor,
-// ) -> std::io::Result<()> {
-//     let mut buf = [0; 512];
-//
-//     let stream = streams.get_mut(&fd).unwrap();
-//     match stream.read(&mut buf) {
-//         Ok(amount) if amount > 0 => {
-//             let data = parse_array(&buf).unwrap().1;
-//
-//             let mut cmd_parts = data.into_iter();
-//             match cmd_parts.next().unwrap().to_ascii_uppercase().as_str() {
-//                 "PING" => send_simple_string(stream, "PONG"),
-//                 "ECHO" => send_bulk_string(stream, cmd_parts.next().unwrap()),
-//                 "RPUSH" => {
-//                     let key = cmd_parts.next().unwrap();
-//
-//                     let list = db.lists.entry(key.into()).or_default();
-//                     list.content.extend(cmd_parts.map(String::from));
-//
-//                     send_integer(stream, list.content.len());
-//
-//                     while !list.content.is_empty() && !list.waiting.is_empty() {
-//                         let waiting_fd = list.waiting.pop_front().unwrap();
-//                         let waiting_client = streams.get_mut(&waiting_fd).unwrap();
-//
-//                         let element = list.content.drain(..1).next().unwrap();
-//                         send_string_array(waiting_client, &[key.into(), element]);
-//                     }
-//                 }
-//                 "LPUSH" => {
-//                     let key = cmd_parts.next().unwrap();
-//
-//                     let list = db.lists.entry(key.into()).or_default();
-//                     list.content.splice(..0, cmd_parts.map(String::from).rev());
-//
-//                     send_integer(stream, list.content.len());
-//
-//                     while !list.content.is_empty() && !list.waiting.is_empty() {
-//                         let waiting_fd = list.waiting.pop_front().unwrap();
-//                         let waiting_client = streams.get_mut(&waiting_fd).unwrap();
-//
-//                         let element = list.content.drain(..1).next().unwrap();
-//                         send_string_array(waiting_client, &[key.into(), element]);
-//                     }
-//                 }
-//                 "LPOP" => {
-//                     let key = cmd_parts.next().unwrap();
-//
-//                     let list = db.lists.entry(key.into()).or_default();
-//
-//                     let amount = cmd_parts.next().map_or(1, |s| s.parse::<usize>().unwrap());
-//                     match list.content.len() {
-//                         0 => send_null_bulk_string(stream),
-//                         _ if amount > 1 => send_string_array(
-//                             stream,
-//                             list.content.drain(0..amount).collect::<Vec<_>>().as_slice(),
-//                         ),
-//                         _ => send_bulk_string(stream, &list.content.remove(0)),
-//                     }
-//                 }
-//                 "GET" => {
-//                     let key = cmd_parts.next().unwrap();
-//
-//                     match db.values.get(key) {
-//                         Some(value) => send_bulk_string(stream, value),
-//                         _ => send_null_bulk_string(stream),
-//                     }
-//                 }
-//                 "SET" => {
-//                     let key = cmd_parts.next().unwrap();
-//                     let value = cmd_parts.next().unwrap();
-//                     if "PX"
-//                         == cmd_parts
-//                             .next()
-//                             .map_or(String::new(), |s| s.to_ascii_uppercase())
-//                     {
-//                         let timeout = Instant::now()
-//                             + Duration::from_millis(cmd_parts.next().unwrap().parse().unwrap());
-//                         event_loop.register_timeout(timeout, Event::InvalidateEntry(key.into()));
-//                     }
-//
-//                     _ = db.values.insert(key.to_string(), value.to_string());
-//
-//                     send_simple_string(stream, "OK")
-//                 }
-//                 "LRANGE" => {
-//                     let key = cmd_parts.next().unwrap();
-//
-//                     let start_idx = cmd_parts.next().unwrap().parse().unwrap();
-//                     let end_idx = cmd_parts.next().unwrap().parse().unwrap();
-//
-//                     match db.lists.get(key) {
-//                         Some(list) if !list.content.is_empty() => {
-//                             let range = handle_index(start_idx, list.content.len())
-//                                 ..=handle_index(end_idx, list.content.len());
-//                             send_string_array(stream, &list.content[range]);
-//                         }
-//                         _ => {
-//                             send_string_array(stream, &[]);
-//                         }
-//                     }
-//                 }
-//                 "LLEN" => {
-//                     let key = cmd_parts.next().unwrap();
-//                     match db.lists.get(key) {
-//                         Some(list) => send_integer(stream, list.content.len()),
-//                         None => send_integer(stream, 0),
-//                     }
-//                 }
-//                 "BLPOP" => {
-//                     let key = cmd_parts.next().unwrap();
-//                     let timeout: f32 = cmd_parts.next().unwrap().parse().unwrap();
-//
-//                     let list = db.lists.entry(key.into()).or_default();
-//                     if list.content.is_empty() {
-//                         if timeout > 0.0 {
-//                             event_loop.register_timeout(
-//                                 Instant::now() + Duration::from_secs_f32(timeout),
-//                                 Event::StopWaiting(stream.as_raw_fd(), key.into()),
-//                             );
-//                         }
-//                         list.waiting.push_back(fd);
-//                     } else {
-//                         let element = list.content.drain(..1).next().unwrap();
-//                         send_string_array(stream, &[key.into(), element]);
-//                     }
-//                 }
-//                 _ => unimplemented!(),
-//             }
-//         }
-//         Ok(_) => {}
-//         Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-//         Err(e) => return Err(e),
-//     }
-//
-//     Ok(())
-// }
-//
-// fn handle_index(index: isize, list_len: usize) -> usize {
-//     let abs_index = if index < 0 {
-//         let abs = usize::try_from(index.abs()).unwrap();
-//         list_len.saturating_sub(abs)
-//     } else {
-//         index.try_into().unwrap()
-//     };
-//
-//     abs_index.min(list_len - 1)
-// }
-//
-async fn send_string_array(stream: &mut TcpStream, data: &[String]) {
-    stream
-        .write_all(format!("*{}\r\n", data.len()).as_bytes())
-        .await
-        .unwrap();
+        rearm(waiting_fd, db, reactor);
+    }
+}
+
+/// Normalizes a (possibly negative) `LRANGE` index into a list offset. Negative
+/// indices count back from the end and saturate at 0; positive indices are
+/// returned as-is so the caller can detect a start that runs past the end
+/// rather than silently clamping it to the last element.
+fn handle_index(index: isize, list_len: usize) -> usize {
+    if index < 0 {
+        let abs = usize::try_from(index.abs()).unwrap();
+        list_len.saturating_sub(abs)
+    } else {
+        index.try_into().unwrap()
+    }
+}
+
+fn client(db: &mut Database, fd: RawFd) -> &mut Client {
+    db.clients.get_mut(&fd).unwrap()
+}
+
+fn send_string_array(client: &mut Client, data: &[String]) {
+    client.enqueue(format!("*{}\r\n", data.len()).as_bytes());
 
     for element in data {
-        send_bulk_string(stream, element).await;
+        send_bulk_string(client, element);
     }
 }
 
-async fn send_null_bulk_string(stream: &mut TcpStream) {
-    stream.write_all(b"$-1\r\n").await.unwrap();
+fn send_null_bulk_string(client: &mut Client) {
+    client.enqueue(b"$-1\r\n");
 }
 
-async fn send_bulk_string(stream: &mut TcpStream, data: &str) {
-    stream
-        .write_all(format!("${}\r\n{}\r\n", data.len(), data).as_bytes())
-        .await
-        .unwrap();
+fn send_null_array(client: &mut Client) {
+    client.enqueue(b"*-1\r\n");
 }
 
-async fn send_simple_string(stream: &mut TcpStream, data: &str) {
-    stream
-        .write_all(format!("+{data}\r\n").as_bytes())
-        .await
-        .unwrap();
+fn send_bulk_string(client: &mut Client, data: &str) {
+    client.enqueue(format!("${}\r\n{}\r\n", data.len(), data).as_bytes());
 }
 
-async fn send_integer(stream: &mut TcpStream, value: usize) {
-    stream
-        .write_all(format!(":{value}\r\n").as_bytes())
-        .await
-        .unwrap()
+fn send_simple_string(client: &mut Client, data: &str) {
+    client.enqueue(format!("+{data}\r\n").as_bytes());
+}
+
+fn send_integer(client: &mut Client, value: usize) {
+    client.enqueue(format!(":{value}\r\n").as_bytes());
+}
+
+fn send_error(client: &mut Client, message: &str) {
+    client.enqueue(format!("-{message}\r\n").as_bytes());
 }
 
 fn parse_array(input: &[u8]) -> IResult<&[u8], Vec<&str>> {
@@ -476,3 +619,135 @@ fn parse_number(input: &[u8]) -> IResult<&[u8], usize> {
     let digits = map_res(take_while(|b: u8| b.is_ascii_digit()), std::str::from_utf8);
     map_res(digits, str::parse::<usize>).parse(input)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Spins up a throwaway loopback listener and returns the accepted server
+    /// side paired with the connected client end, so a test can register a real
+    /// fd in the `Database` without a running event loop.
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        (server, client)
+    }
+
+    /// Registers a connected fd both in the `Database` and with the reactor's
+    /// epoll, so event-loop paths that re-arm interest (`rearm`) don't trip on
+    /// an unknown fd.
+    fn register(db: &mut Database, reactor: &mut Reactor, stream: TcpStream) -> RawFd {
+        let fd = stream.as_raw_fd();
+        reactor.register_oneshot(stream.as_fd());
+        db.clients.insert(fd, Client::new(stream));
+        fd
+    }
+
+    fn read_reply(peer: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        peer.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn disconnect_cancels_blpop_waiter() {
+        let mut db = Database::default();
+        let mut reactor = Reactor::default();
+
+        // A client parks on an empty key via `BLPOP key 0`.
+        let (waiter, _waiter_peer) = connected_pair();
+        let waiter_fd = register(&mut db, &mut reactor, waiter);
+        handle_request(&["BLPOP", "key", "0"], waiter_fd, &mut db, &mut reactor);
+        assert_eq!(db.lists["key"].waiting, VecDeque::from([waiter_fd]));
+
+        // It goes away before anything is pushed.
+        disconnect_client(waiter_fd, &mut db);
+        assert!(!db.clients.contains_key(&waiter_fd));
+        assert!(db.lists["key"].waiting.is_empty());
+
+        // A later push must keep the element rather than hand it to the gone fd.
+        let (pusher, _pusher_peer) = connected_pair();
+        let pusher_fd = register(&mut db, &mut reactor, pusher);
+        handle_request(&["RPUSH", "key", "value"], pusher_fd, &mut db, &mut reactor);
+
+        assert_eq!(db.lists["key"].content, vec!["value".to_string()]);
+        assert!(db.lists["key"].waiting.is_empty());
+    }
+
+    #[test]
+    fn data_received_event_processes_a_command() {
+        let mut db = Database::default();
+        let mut reactor = Reactor::default();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        let (server, mut peer) = connected_pair();
+        let fd = register(&mut db, &mut reactor, server);
+
+        peer.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n")
+            .unwrap();
+        dispatch(Event::DataReceived(fd), &mut db, &mut reactor, &listener);
+
+        assert_eq!(db.values.get("foo"), Some(&"bar".to_string()));
+        assert_eq!(read_reply(&mut peer, 5), b"+OK\r\n");
+    }
+
+    #[test]
+    fn invalidate_entry_event_respects_supersede() {
+        let mut db = Database::default();
+        let mut reactor = Reactor::default();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let (client, _peer) = connected_pair();
+        let fd = register(&mut db, &mut reactor, client);
+
+        // `SET k v1 PX 100` schedules generation 0; `SET k v2` supersedes it.
+        handle_request(&["SET", "k", "v1", "PX", "100"], fd, &mut db, &mut reactor);
+        handle_request(&["SET", "k", "v2"], fd, &mut db, &mut reactor);
+
+        // The stale timer firing must not evict the newer value.
+        dispatch(
+            Event::InvalidateEntry("k".to_string(), 0),
+            &mut db,
+            &mut reactor,
+            &listener,
+        );
+        assert_eq!(db.values.get("k"), Some(&"v2".to_string()));
+
+        // The current generation still evicts.
+        dispatch(
+            Event::InvalidateEntry("k".to_string(), 1),
+            &mut db,
+            &mut reactor,
+            &listener,
+        );
+        assert!(!db.values.contains_key("k"));
+    }
+
+    #[test]
+    fn stop_waiting_event_resolves_blocked_client() {
+        let mut db = Database::default();
+        let mut reactor = Reactor::default();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let (client, mut peer) = connected_pair();
+        let fd = register(&mut db, &mut reactor, client);
+
+        // Positive timeout parks the client and schedules a `StopWaiting`.
+        handle_request(&["BLPOP", "k", "1"], fd, &mut db, &mut reactor);
+        assert_eq!(db.lists["k"].waiting, VecDeque::from([fd]));
+
+        dispatch(
+            Event::StopWaiting(fd, "k".to_string()),
+            &mut db,
+            &mut reactor,
+            &listener,
+        );
+
+        assert!(db.lists["k"].waiting.is_empty());
+        assert_eq!(read_reply(&mut peer, 5), b"*-1\r\n");
+    }
+}